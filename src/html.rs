@@ -1,9 +1,132 @@
-use minijinja::Environment;
+use std::path::Path;
+use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use crate::Cli;
+use minijinja::{context, Environment};
+use regex::{Captures, Regex};
 
-pub fn generate(config: Cli) -> anyhow::Result<String> {
-    let raw = r#"
+use crate::{retry_read, BocaError, Cli, ASSET_PREFIX};
+
+/// Matches `src="..."` and `href="..."` attributes so relative ones can be routed through
+/// [`ASSET_PREFIX`].
+static REL_ATTR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(src|href)="([^"]+)""#).unwrap());
+
+/// True if `url` is relative to the previewed file rather than absolute, same-page, or a data URI.
+fn is_relative(url: &str) -> bool {
+    !(url.starts_with("http://")
+        || url.starts_with("https://")
+        || url.starts_with('/')
+        || url.starts_with('#')
+        || url.starts_with("mailto:")
+        || url.starts_with("data:"))
+}
+
+/// Prefix a path with [`ASSET_PREFIX`] if it's relative, so it resolves against the `ServeDir`
+/// mounted over the previewed file's parent directory.
+pub(crate) fn asset_url(path: &str) -> String {
+    if is_relative(path) {
+        format!("{ASSET_PREFIX}/{path}")
+    } else {
+        path.to_string()
+    }
+}
+
+/// True if `url` (ignoring any query string or fragment) points at another markdown file, or at
+/// a path with no extension at all. Those are page links handled by the `/*filename` catch-all
+/// and `link_handler`, not assets, and must not be routed through [`ASSET_PREFIX`].
+fn is_markdown_link(url: &str) -> bool {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    match Path::new(path).extension() {
+        Some(ext) => ext.eq_ignore_ascii_case("md") || ext.eq_ignore_ascii_case("markdown"),
+        None => true,
+    }
+}
+
+/// Rewrite every relative `src`/`href` attribute in rendered HTML to point through `prefix`, so
+/// images, stylesheets, and downloads relative to the previewed markdown file resolve. Relative
+/// `href`s to other markdown pages are left alone so they keep going through `link_handler`.
+pub(crate) fn rewrite_relative_urls(html: &str, prefix: &str) -> String {
+    REL_ATTR
+        .replace_all(html, |caps: &Captures| {
+            let attr = &caps[1];
+            let url = &caps[2];
+            let is_page_link = attr == "href" && is_markdown_link(url);
+            if is_relative(url) && !is_page_link {
+                format!(r#"{attr}="{prefix}/{url}""#)
+            } else {
+                caps[0].to_string()
+            }
+        })
+        .into_owned()
+}
+
+/// Render the `<link>` tag for the custom stylesheet, wired up for htmx to hot-swap it in place
+/// (via its own `sse-swap="style"` attribute) whenever `--stylesheet` changes on disk. The
+/// query string busts the browser's cache so the swapped-in link is actually refetched.
+pub(crate) fn stylesheet_tag(stylesheet: &str) -> String {
+    let href = asset_url(stylesheet);
+    let bust = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis())
+        .unwrap_or_default();
+    format!(
+        r#"<link id="user-stylesheet" href="{href}?t={bust}" rel="stylesheet" sse-swap="style" hx-swap="outerHTML"/>"#
+    )
+}
+
+pub async fn generate(config: Cli) -> Result<String, BocaError> {
+    // make sure the file is actually there (and readable) before building a page around it
+    retry_read(&config.filename)
+        .await
+        .map_err(|e| e.with_dark(config.dark))?;
+
+    let config = Cli {
+        stylesheet: config.stylesheet.as_deref().map(stylesheet_tag),
+        ..config
+    };
+    let dark = config.dark;
+    let filename = config.filename.clone();
+
+    render(config).map_err(|source| BocaError::Render { filename, dark, source })
+}
+
+fn render(config: Cli) -> anyhow::Result<String> {
+    let mut env = Environment::new();
+    if let Some(template_path) = &config.template {
+        let path = Path::new(template_path);
+        let dir = path
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow::anyhow!("{template_path} has no file name"))?
+            .to_string_lossy()
+            .to_string();
+        env.set_loader(minijinja::path_loader(dir));
+        let tmpl = env.get_template(&name)?;
+        Ok(tmpl.render(config)?)
+    } else {
+        env.add_template("root", DEFAULT_TEMPLATE)?;
+        let tmpl = env.get_template("root")?;
+        Ok(tmpl.render(config)?)
+    }
+}
+
+/// Render a styled error page (matching `--dark`) instead of a plain-text body. The page keeps
+/// listening on `filename`'s SSE endpoint and swaps itself out for the real content as soon as
+/// it becomes available, the same way the normal template hot-swaps on file changes - so a
+/// transient failure (e.g. the MOVE_SELF race `retry_read` tolerates) self-heals instead of
+/// leaving the user stuck on a dead page.
+pub(crate) fn generate_error(dark: bool, filename: &str, message: &str) -> anyhow::Result<String> {
+    let mut env = Environment::new();
+    env.add_template("error", ERROR_TEMPLATE)?;
+    let tmpl = env.get_template("error")?;
+    Ok(tmpl.render(context! { dark, filename, message })?)
+}
+
+const DEFAULT_TEMPLATE: &str = r#"
     <!doctype html>
     <html lang="en">
         <head>
@@ -46,21 +169,102 @@ pub fn generate(config: Cli) -> anyhow::Result<String> {
                         background: light-dark(#EDEDED, #686868);
                     }
                 </style>
-
-                {% if stylesheet %}<link href="{{stylesheet}}" rel="stylesheet"/>{% endif %}
         </head>
-        <body>
+        <body hx-ext="sse" sse-connect="/sse/{{filename|escape}}">
+            {% if stylesheet %}{{stylesheet}}{% endif %}
             <div class="text-body">
-                <span id="data-value" hx-ext="sse" sse-connect="/sse/{{filename}}" sse-swap="body" >
-                
+                <span id="data-value" sse-swap="body" hx-swap="innerHTML">
+
                 Loading...</span>
             </div>
+            <script>
+                document.body.addEventListener('htmx:sseMessage', (e) => {
+                    if (e.detail.type === 'reload') {
+                        window.location.reload();
+                    }
+                });
+            </script>
         </body>
     </html>
     "#;
-    let mut env = Environment::new();
-    env.add_template("root", raw)?;
-    let tmpl = env.get_template("root")?;
-    let rendered = tmpl.render(config)?;
-    Ok(rendered)
-}
\ No newline at end of file
+
+const ERROR_TEMPLATE: &str = r#"
+    <!doctype html>
+    <html lang="en">
+        <head>
+            <script src="https://unpkg.com/htmx.org@2.0.3"></script>
+            <script src="https://unpkg.com/htmx-ext-sse@2.2.2/sse.js"></script>
+            <style>
+                :root {
+                    color-scheme: {% if dark %} dark {% else %} light {% endif %};
+                }
+                body {
+                    display: flex;
+                    align-items: center;
+                    justify-content: center;
+                }
+                blockquote {
+                    margin:10px auto;
+                    font-style:italic;
+                    padding:1.0em 30px 1.2em 75px;
+                    border-left:8px solid #f36d33;
+                    line-height:1.6;
+                    position: relative;
+                    background: light-dark(#EDEDED, #686868);
+                }
+            </style>
+        </head>
+        <body hx-ext="sse" sse-connect="/sse/{{filename|escape}}">
+            <div class="text-body">
+                <span id="data-value" sse-swap="body" hx-swap="innerHTML">
+                <blockquote>{{ message|escape }}</blockquote>
+                </span>
+            </div>
+        </body>
+    </html>
+    "#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_relative_excludes_absolute_and_special_urls() {
+        assert!(is_relative("image.png"));
+        assert!(is_relative("sub/dir/image.png"));
+        assert!(!is_relative("http://example.com/image.png"));
+        assert!(!is_relative("https://example.com/image.png"));
+        assert!(!is_relative("/image.png"));
+        assert!(!is_relative("#section"));
+        assert!(!is_relative("mailto:a@example.com"));
+        assert!(!is_relative("data:image/png;base64,abcd"));
+    }
+
+    #[test]
+    fn is_markdown_link_matches_markdown_extensions_and_extensionless_paths() {
+        assert!(is_markdown_link("other.md"));
+        assert!(is_markdown_link("other.MARKDOWN"));
+        assert!(is_markdown_link("other.md?foo=bar#frag"));
+        assert!(is_markdown_link("other"));
+        assert!(!is_markdown_link("image.png"));
+        assert!(!is_markdown_link("style.css"));
+    }
+
+    #[test]
+    fn rewrite_relative_urls_rewrites_assets_but_not_markdown_links() {
+        let html = r#"<img src="image.png"><a href="other.md">other</a><a href="https://example.com">ext</a>"#;
+        let rewritten = rewrite_relative_urls(html, "/_assets");
+        assert!(rewritten.contains(r#"src="/_assets/image.png""#));
+        assert!(rewritten.contains(r#"href="other.md""#));
+        assert!(rewritten.contains(r#"href="https://example.com""#));
+    }
+
+    #[test]
+    fn stylesheet_tag_renders_a_link_element_not_a_sentinel() {
+        let tag = stylesheet_tag("style.css");
+        assert!(tag.starts_with("<link"));
+        assert!(tag.contains(r#"sse-swap="style""#));
+        assert!(tag.contains(r#"hx-swap="outerHTML""#));
+        assert_ne!(tag, "reload");
+    }
+}
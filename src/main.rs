@@ -11,8 +11,14 @@ use tokio_stream::{Stream, StreamExt};
 use clap::Parser;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use tracing::{debug, error, info, span, Instrument, Level};
+use tower_http::services::ServeDir;
 
 mod html;
+mod watchman;
+
+/// URL prefix under which the directory containing the previewed file is served, so that
+/// relative images/links/stylesheets in the markdown resolve without a second static server.
+pub(crate) const ASSET_PREFIX: &str = "/_assets";
 
 
 /// The Cli. Implements Serialize so we can send it right to the templating engine that renders HTML
@@ -31,6 +37,11 @@ struct Cli {
     #[serde(skip_serializing_if = "Option::is_none")]
     stylesheet: Option<String>,
 
+    /// Supply a custom minijinja template for the preview page, instead of the built-in one.
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    template: Option<String>,
+
     /// Run web page in dark mode.
     #[arg(long)]
     dark: bool,
@@ -43,6 +54,10 @@ struct Cli {
     #[arg(short, long)]
     inotify: bool,
 
+    /// Watch the file through a Watchman server instead of inotify/polling
+    #[arg(short, long)]
+    watchman: bool,
+
     /// Render unsafe HTML in markdown. Only use for trusted files
     #[arg(long)]
     html: bool,
@@ -83,10 +98,17 @@ async fn main() -> anyhow::Result<()> {
     .with(tracing_subscriber::fmt::layer())
     .init();
 
+    let asset_dir = Path::new(&cli.filename)
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+        .to_path_buf();
+
     let app = Router::new()
     .route("/", get(root))
     .route("/sse/:path", get(sse_handler))
     .route("/*filename", get(link_handler))
+    .nest_service(ASSET_PREFIX, ServeDir::new(asset_dir))
     .layer(
         tower_http::trace::TraceLayer::new_for_http()
     )
@@ -100,10 +122,10 @@ async fn main() -> anyhow::Result<()> {
 }
 
 /// handler for /sse
-async fn sse_handler(State(state): State<Cli>, axum::extract::Path(path): axum::extract::Path<String>) 
--> Sse<impl Stream<Item = Result<Event, anyhow::Error>>> {
+async fn sse_handler(State(state): State<Cli>, axum::extract::Path(path): axum::extract::Path<String>)
+-> Sse<impl Stream<Item = Result<Event, BocaError>>> {
     let newstate = Cli{filename: path.clone(), ..state};
-    let (tx, rx) = channel::<Result<Event, anyhow::Error>>(30);
+    let (tx, rx) = channel::<Result<Event, BocaError>>(30);
 
     let fspan = span!(Level::DEBUG, "file_watch", file=&path);
     tokio::spawn(async move {
@@ -133,18 +155,22 @@ async fn sse_handler(State(state): State<Cli>, axum::extract::Path(path): axum::
 async fn link_handler(State(state): State<Cli>, axum::extract::Path(path): axum::extract::Path<String>)  -> Result<Html<String>, BocaError> {
     info!{%path, "rendering new file"};
     let newstate = Cli{filename: path, ..state};
-    let raw = generate(newstate)?;
+    let raw = generate(newstate).await?;
     Ok(Html(raw.to_string()))
 }
 
 /// handler for  /
 async fn root(State(state): State<Cli>) -> Result<Html<String>, BocaError> {
-    let raw = generate(state)?;
+    let raw = generate(state).await?;
     Ok(Html(raw.to_string()))
 }
 
 /// blocks until the receiver closes, waits and sends file updates
-async fn file_watch(tx: Sender<Result<Event, anyhow::Error>>, opts: Cli) -> anyhow::Result<()> {
+async fn file_watch(tx: Sender<Result<Event, BocaError>>, opts: Cli) -> anyhow::Result<()> {
+    if opts.watchman {
+        return watchman::watch(tx, opts).await;
+    }
+
     debug!("starting new file notify watcher");
     //initialize with base file event
     tx.send(read_to_event(&opts.filename, opts.html).await).await?;
@@ -162,26 +188,42 @@ async fn file_watch(tx: Sender<Result<Event, anyhow::Error>>, opts: Cli) -> anyh
         notify::Config::default().with_poll_interval(Duration::from_secs(1)),
     )?;
 
-    let path = Path::new(&opts.filename);
-    watcher.watch(path, notify::RecursiveMode::Recursive)?;
+    let main_path = Path::new(&opts.filename).to_path_buf();
+    watcher.watch(&main_path, notify::RecursiveMode::Recursive)?;
 
+    // watch the stylesheet and template too, reusing the same watcher, so editing either pushes
+    // a live update instead of requiring a restart
+    if let Some(stylesheet) = &opts.stylesheet {
+        watcher.watch(Path::new(stylesheet), notify::RecursiveMode::Recursive)?;
+    }
+    if let Some(template) = &opts.template {
+        watcher.watch(Path::new(template), notify::RecursiveMode::Recursive)?;
+    }
 
     while let Some(evt) = watch_rx.recv().await {
-        
+
         let file_evt = evt?;
-        if file_evt.kind.is_modify() {
-            let path = file_evt.paths[0].clone();
-            let monitor_path = path.to_string_lossy().to_string();
+        if !file_evt.kind.is_modify() {
+            continue;
+        }
+        let path = file_evt.paths[0].clone();
+        let monitor_path = path.to_string_lossy().to_string();
+        if path == main_path {
             debug!{%monitor_path, "updating file"};
-            tx.send(read_to_event(path, opts.html).await).await?;
+            tx.send(read_to_event(&path, opts.html).await).await?;
+        } else if let Some(stylesheet) = opts.stylesheet.as_deref().filter(|s| path == Path::new(s)) {
+            debug!{%monitor_path, "stylesheet changed"};
+            tx.send(Ok(Event::default().data(html::stylesheet_tag(stylesheet)).event("style"))).await?;
+        } else if opts.template.as_deref().is_some_and(|t| path == Path::new(t)) {
+            debug!{%monitor_path, "template changed"};
+            tx.send(Ok(Event::default().data("reload").event("reload"))).await?;
         }
-
     }
     Ok(())
 }
 
 /// turn a filepath into a complete SSE event from parsed markdown
-async fn read_to_event<P: AsRef<Path>>(filepath: P, html_mode: bool) -> Result<Event, anyhow::Error>{
+pub(crate) async fn read_to_event<P: AsRef<Path>>(filepath: P, html_mode: bool) -> Result<Event, BocaError>{
     let md = retry_read(&filepath).await?;
     let mut md_opts = Options::gfm();
     if html_mode {
@@ -196,13 +238,15 @@ async fn read_to_event<P: AsRef<Path>>(filepath: P, html_mode: bool) -> Result<E
             m.to_string()
         }
     };
+    let res_html = html::rewrite_relative_urls(&res_html, ASSET_PREFIX);
     Ok(Event::default().data(res_html).event("body"))
 
 }
 
 /// The MOVE_SELF behavior of vim tends to produce race conditions, we might try to read a file while vim is moving things around.
-async fn retry_read<P: AsRef<Path>>(filepath: P) -> anyhow::Result<String> {
+pub(crate) async fn retry_read<P: AsRef<Path>>(filepath: P) -> Result<String, BocaError> {
     let count = 3;
+    let mut last_err = None;
     for _i in 0..count {
         match read_to_string(&filepath) {
             Ok(r) => {
@@ -210,31 +254,152 @@ async fn retry_read<P: AsRef<Path>>(filepath: P) -> anyhow::Result<String> {
             }
             Err(e) => {
                 error!("error reading file, retrying: {e}");
+                last_err = Some(e);
             }
         }
         tokio::time::sleep(Duration::from_millis(300)).await
     };
 
-    Err(anyhow!("Could not read from file {}", filepath.as_ref().to_string_lossy()))
+    let filename = filepath.as_ref().to_string_lossy().to_string();
+    Err(match last_err {
+        Some(e) if e.kind() == std::io::ErrorKind::NotFound => BocaError::NotFound { filename, dark: false },
+        Some(e) => BocaError::Unreadable { filename, dark: false, source: e },
+        None => BocaError::Internal { filename, dark: false, source: anyhow!("retry loop exited without reading the file") },
+    })
 }
 
-struct BocaError(anyhow::Error);
+/// Errors that can surface while serving a preview page, distinguished so the browser gets a
+/// real HTTP status and a styled error page instead of a generic 500 with a plain-text body.
+///
+/// Every variant carries the `filename` the request was for, so the error page can keep
+/// listening on that file's SSE endpoint and swap itself out once the problem clears (e.g. the
+/// MOVE_SELF race `retry_read` itself tolerates, if an editor is still mid-save when the page is
+/// first requested).
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum BocaError {
+    #[error("{filename} not found")]
+    NotFound { filename: String, dark: bool },
+
+    #[error("could not read {filename}: {source}")]
+    Unreadable {
+        filename: String,
+        dark: bool,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("failed to render page")]
+    Render {
+        filename: String,
+        dark: bool,
+        #[source]
+        source: anyhow::Error,
+    },
+
+    #[error("internal error")]
+    Internal {
+        filename: String,
+        dark: bool,
+        #[source]
+        source: anyhow::Error,
+    },
+}
+
+impl BocaError {
+    fn status(&self) -> StatusCode {
+        match self {
+            BocaError::NotFound { .. } => StatusCode::NOT_FOUND,
+            BocaError::Unreadable { source, .. } if source.kind() == std::io::ErrorKind::PermissionDenied => StatusCode::FORBIDDEN,
+            BocaError::Unreadable { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            BocaError::Render { .. } | BocaError::Internal { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn dark(&self) -> bool {
+        match self {
+            BocaError::NotFound { dark, .. }
+            | BocaError::Unreadable { dark, .. }
+            | BocaError::Render { dark, .. }
+            | BocaError::Internal { dark, .. } => *dark,
+        }
+    }
+
+    fn filename(&self) -> &str {
+        match self {
+            BocaError::NotFound { filename, .. }
+            | BocaError::Unreadable { filename, .. }
+            | BocaError::Render { filename, .. }
+            | BocaError::Internal { filename, .. } => filename,
+        }
+    }
+
+    /// Attach the page's dark-mode preference, once it's known, so the rendered error page
+    /// matches the preview the user was expecting.
+    fn with_dark(self, dark: bool) -> Self {
+        match self {
+            BocaError::NotFound { filename, .. } => BocaError::NotFound { filename, dark },
+            BocaError::Unreadable { filename, source, .. } => BocaError::Unreadable { filename, source, dark },
+            BocaError::Render { filename, source, .. } => BocaError::Render { filename, source, dark },
+            BocaError::Internal { filename, source, .. } => BocaError::Internal { filename, source, dark },
+        }
+    }
+}
 
 impl IntoResponse for BocaError {
     fn into_response(self) -> axum::response::Response {
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Something went wrong: {}", self.0),
-        ).into_response()
+        error!("request failed: {self}");
+        let status = self.status();
+        let dark = self.dark();
+        let filename = self.filename().to_string();
+        let message = self.to_string();
+        let body = html::generate_error(dark, &filename, &message)
+            .unwrap_or_else(|_| format!("Something went wrong: {message}"));
+        (status, Html(body)).into_response()
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn not_found() -> BocaError {
+        BocaError::NotFound { filename: "missing.md".into(), dark: false }
+    }
+
+    fn unreadable(kind: std::io::ErrorKind) -> BocaError {
+        BocaError::Unreadable {
+            filename: "locked.md".into(),
+            dark: false,
+            source: std::io::Error::new(kind, "nope"),
+        }
+    }
+
+    fn render_error() -> BocaError {
+        BocaError::Render { filename: "broken.md".into(), dark: false, source: anyhow!("bad template") }
+    }
+
+    fn internal_error() -> BocaError {
+        BocaError::Internal { filename: "broken.md".into(), dark: false, source: anyhow!("boom") }
+    }
+
+    #[test]
+    fn status_maps_not_found_to_404() {
+        assert_eq!(not_found().status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn status_maps_permission_denied_to_403() {
+        assert_eq!(unreadable(std::io::ErrorKind::PermissionDenied).status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn status_maps_other_unreadable_errors_to_500() {
+        assert_eq!(unreadable(std::io::ErrorKind::Other).status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
 
-impl<E> From<E> for BocaError
-where
-    E: Into<anyhow::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
+    #[test]
+    fn status_maps_render_and_internal_errors_to_500() {
+        assert_eq!(render_error().status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(internal_error().status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
 }
\ No newline at end of file
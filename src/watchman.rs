@@ -0,0 +1,175 @@
+use std::path::PathBuf;
+
+use axum::response::sse::Event;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+use tokio::process::Command;
+use tokio::sync::mpsc::Sender;
+use tracing::{debug, error, trace};
+
+use crate::{read_to_event, BocaError, Cli};
+
+const MAIN_SUBSCRIPTION: &str = "boca-main";
+const STYLE_SUBSCRIPTION: &str = "boca-style";
+const TEMPLATE_SUBSCRIPTION: &str = "boca-template";
+
+/// Ask the locally running Watchman daemon for the path to its unix socket.
+async fn sockname() -> anyhow::Result<PathBuf> {
+    let out = Command::new("watchman").arg("get-sockname").output().await?;
+    if !out.status.success() {
+        anyhow::bail!(
+            "watchman get-sockname failed: {}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+    let parsed: Value = serde_json::from_slice(&out.stdout)?;
+    let sock = parsed
+        .get("sockname")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow::anyhow!("watchman get-sockname response missing sockname"))?;
+    Ok(PathBuf::from(sock))
+}
+
+/// Send a single command over the Watchman JSON protocol and wait for its reply.
+async fn send(
+    write_half: &mut OwnedWriteHalf,
+    reader: &mut BufReader<OwnedReadHalf>,
+    cmd: Value,
+) -> anyhow::Result<Value> {
+    let mut payload = serde_json::to_vec(&cmd)?;
+    payload.push(b'\n');
+    write_half.write_all(&payload).await?;
+
+    let mut line = String::new();
+    let resp: Value = loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            anyhow::bail!("watchman connection closed");
+        }
+        let resp: Value = serde_json::from_str(&line)?;
+        // notifications for an earlier subscription can arrive ahead of this command's reply
+        if resp.get("unilateral").is_none() && resp.get("subscription").is_none() {
+            break resp;
+        }
+    };
+    if let Some(err) = resp.get("error").and_then(Value::as_str) {
+        anyhow::bail!("watchman error: {err}");
+    }
+    Ok(resp)
+}
+
+/// Resolve `path`'s parent directory with Watchman and subscribe to just that file under `name`,
+/// mirroring what the notify backend does by watching it directly.
+async fn subscribe(
+    write_half: &mut OwnedWriteHalf,
+    reader: &mut BufReader<OwnedReadHalf>,
+    path: &str,
+    name: &str,
+) -> anyhow::Result<()> {
+    let path = std::path::Path::new(path).canonicalize()?;
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("{} has no parent directory", path.display()))?;
+    let basename = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("{} has no file name", path.display()))?
+        .to_string_lossy()
+        .to_string();
+
+    let watch_project = send(write_half, reader, json!(["watch-project", dir])).await?;
+    let watch_root = watch_project
+        .get("watch")
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow::anyhow!("watch-project response missing watch root"))?
+        .to_string();
+    let relative_path = watch_project
+        .get("relative_path")
+        .and_then(Value::as_str)
+        .unwrap_or("")
+        .to_string();
+
+    let subscribe_cmd = json!(["subscribe", watch_root, name, {
+        "expression": ["name", basename, "wholename"],
+        "fields": ["name", "exists"],
+        "relative_root": relative_path,
+    }]);
+    send(write_half, reader, subscribe_cmd).await?;
+    Ok(())
+}
+
+/// Watch `opts.filename` (and, if set, `opts.stylesheet`/`opts.template`) via Watchman
+/// subscriptions instead of notify's inotify/poll backends. Watchman scales far better than
+/// inotify on large trees and, unlike polling, doesn't busy-loop.
+///
+/// Edits to the main file still go through `read_to_event`/`retry_read`, so the MOVE_SELF retry
+/// behavior for editors that replace files atomically (e.g. vim) applies here too.
+pub(crate) async fn watch(tx: Sender<Result<Event, BocaError>>, opts: Cli) -> anyhow::Result<()> {
+    debug!("starting new watchman subscription");
+
+    let sock = sockname().await?;
+    debug!(socket=%sock.display(), "connecting to watchman");
+    let stream = UnixStream::connect(&sock).await?;
+    let (read_half, mut write_half) = stream.into_split();
+    let mut reader = BufReader::new(read_half);
+
+    subscribe(&mut write_half, &mut reader, &opts.filename, MAIN_SUBSCRIPTION).await?;
+    if let Some(stylesheet) = &opts.stylesheet {
+        subscribe(&mut write_half, &mut reader, stylesheet, STYLE_SUBSCRIPTION).await?;
+    }
+    if let Some(template) = &opts.template {
+        subscribe(&mut write_half, &mut reader, template, TEMPLATE_SUBSCRIPTION).await?;
+    }
+
+    // initialize with base file event, same as the notify path
+    tx.send(read_to_event(&opts.filename, opts.html).await).await?;
+
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let n = reader.read_line(&mut line).await?;
+        if n == 0 {
+            anyhow::bail!("watchman connection closed");
+        }
+
+        let msg: Value = match serde_json::from_str(&line) {
+            Ok(v) => v,
+            Err(e) => {
+                error!("error parsing watchman notification, skipping: {e}");
+                continue;
+            }
+        };
+
+        let Some(subscription) = msg.get("subscription").and_then(Value::as_str) else {
+            continue;
+        };
+        let Some(files) = msg.get("files").and_then(Value::as_array) else {
+            continue;
+        };
+        if !files
+            .iter()
+            .any(|f| f.get("exists").and_then(Value::as_bool).unwrap_or(false))
+        {
+            continue;
+        }
+
+        match subscription {
+            MAIN_SUBSCRIPTION => {
+                trace!("watchman reported file change");
+                tx.send(read_to_event(&opts.filename, opts.html).await).await?;
+            }
+            STYLE_SUBSCRIPTION => {
+                debug!("watchman reported stylesheet change");
+                if let Some(stylesheet) = &opts.stylesheet {
+                    tx.send(Ok(Event::default().data(crate::html::stylesheet_tag(stylesheet)).event("style"))).await?;
+                }
+            }
+            TEMPLATE_SUBSCRIPTION => {
+                debug!("watchman reported template change");
+                tx.send(Ok(Event::default().data("reload").event("reload"))).await?;
+            }
+            other => trace!(%other, "ignoring unknown watchman subscription"),
+        }
+    }
+}